@@ -95,15 +95,33 @@
 //! Also, `Cell` and `RefCell` allow programmers to separate mutability from
 //! mutable references, so with `Bc<Cell<T>>` the borrow count will never
 //! change, even if the internal `T` is modified.
+//!
+//! # Sharing across threads
+//!
+//! `Bc<T>` uses a `Cell<usize>` to count immutable borrows, which makes it
+//! `!Sync`. If you need to share a counted value across threads, use
+//! `AtomicBc<T>` instead, which uses an `AtomicUsize` and a relaxed memory
+//! ordering to count immutable borrows without data races.
 
 // TODO? Mbc (Mutable Borrow counter) & Cbc (*const* borrow counter) & Bc (*all* borrow counter)
 
 #![deny(missing_docs)]
+use std::cell::Cell;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// The borrow counter struct for type `T`.
+///
+/// This counts both mutable borrows (through `DerefMut`) and immutable
+/// borrows (through `Deref`) separately. The immutable counter is stored in
+/// a `Cell<usize>` so it can be incremented through a shared `&self`, which
+/// means `Bc<T>` is not `Sync` (a `Cell` can't be shared across threads). If
+/// you need to share borrow counting across threads, use `AtomicBc<T>`
+/// instead.
 pub struct Bc<T> {
     counter: usize,
+    imm: Cell<usize>,
+    epoch: u64,
     val: T
 }
 
@@ -113,23 +131,74 @@ impl<T> Bc<T> {
         Bc {
             val: val,
             counter: 0,
+            imm: Cell::new(0),
+            epoch: 0,
         }
     }
 
-    /// Reset the borrow counter
+    /// Reset both the mutable and immutable borrow counters
+    ///
+    /// This does not affect `generation()`/`has_changed_since()`: a
+    /// `Generation` taken before a `reset()` must still compare as changed
+    /// after a later mutation, which `reset()`-able counters can't
+    /// guarantee on their own.
     pub fn reset(&mut self) {
         self.counter = 0;
+        self.imm.set(0);
     }
 
     /// Get the number of time this structure has been mutably borrowed.
+    ///
+    /// This is kept for backward compatibility, and is equivalent to
+    /// `count_mut()`. Be aware that this counter wraps around at
+    /// `usize::MAX`: prefer `generation()`/`has_changed_since()` if you
+    /// need a comparison that can't give a false "unchanged" result.
     pub fn count(&self) -> usize {
         self.counter
     }
+
+    /// Get the number of time this structure has been mutably borrowed.
+    pub fn count_mut(&self) -> usize {
+        self.counter
+    }
+
+    /// Get the number of time this structure has been immutably borrowed.
+    pub fn count_imm(&self) -> usize {
+        self.imm.get()
+    }
+
+    /// Get an opaque token identifying the current point in this value's
+    /// mutation history.
+    ///
+    /// Unlike `count()`, this is backed by a `u64` epoch which is never
+    /// reset and for all practical purposes will not wrap around, so
+    /// comparing two `Generation`s is a safe way to detect "has this value
+    /// been mutated" even across a very large number of mutations.
+    pub fn generation(&self) -> Generation {
+        Generation(self.epoch)
+    }
+
+    /// Check whether this value has been mutably borrowed since `gen` was
+    /// taken.
+    pub fn has_changed_since(&self, gen: Generation) -> bool {
+        self.epoch != gen.0
+    }
+
+    /// Get this value's current borrow state, as a `Touched`/`Untouched`
+    /// enum rather than a raw mutation count.
+    pub fn state(&self) -> BorrowState {
+        if self.counter == 0 {
+            BorrowState::Untouched
+        } else {
+            BorrowState::Touched { mutations: self.counter }
+        }
+    }
 }
 
 impl<T> Deref for Bc<T> {
     type Target = T;
     fn deref(&self) -> &T {
+        self.imm.set(self.imm.get().wrapping_add(1));
         &self.val
     }
 }
@@ -137,10 +206,162 @@ impl<T> Deref for Bc<T> {
 impl<T> DerefMut for Bc<T> {
      fn deref_mut(&mut self) -> &mut T {
          self.counter = self.counter.wrapping_add(1);
+         self.epoch = self.epoch.wrapping_add(1);
          &mut self.val
      }
 }
 
+/// An opaque token identifying a point in a [`Bc<T>`](struct.Bc.html)'s
+/// mutation history.
+///
+/// Store a `Generation` instead of a raw `count()` when you need to detect
+/// "has this value changed since I last looked at it": a fresh `Generation`
+/// never compares equal to one taken after a `deref_mut`, even across
+/// `usize::MAX` mutations, because it is backed by a `u64` epoch rather than
+/// the wrapping `usize` counter. Obtain one with `Bc::generation()` and
+/// compare it with `Bc::has_changed_since()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generation(u64);
+
+/// Whether a [`Bc<T>`](struct.Bc.html) has been mutably borrowed since its
+/// creation or last `reset()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BorrowState {
+    /// The value has not been mutably borrowed.
+    Untouched,
+    /// The value has been mutably borrowed `mutations` times.
+    Touched {
+        /// Number of mutable borrows since creation or the last `reset()`.
+        mutations: usize,
+    },
+}
+
+/// A `Sync` variant of [`Bc<T>`](struct.Bc.html), using an `AtomicUsize` to
+/// count immutable borrows.
+///
+/// This allows sharing a value behind a `&AtomicBc<T>` across threads, with
+/// every `Deref` from any thread bumping the same counter without data
+/// races. The mutable borrow counter does not need to be atomic, since
+/// `&mut self` already guarantees unique access.
+///
+/// The atomic counter is incremented with `Ordering::Relaxed`: this gives no
+/// synchronization guarantees beyond the atomicity of the increment itself,
+/// so `count_imm()` should be seen as eventually consistent rather than as a
+/// point of synchronization between threads.
+pub struct AtomicBc<T> {
+    counter: usize,
+    imm: AtomicUsize,
+    val: T
+}
+
+impl<T> AtomicBc<T> {
+    /// Create a new `AtomicBc<T>` containing the value `val`.
+    pub fn new(val: T) -> AtomicBc<T> {
+        AtomicBc {
+            val: val,
+            counter: 0,
+            imm: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reset both the mutable and immutable borrow counters
+    pub fn reset(&mut self) {
+        self.counter = 0;
+        self.imm.store(0, Ordering::Relaxed);
+    }
+
+    /// Get the number of time this structure has been mutably borrowed.
+    ///
+    /// This is kept consistent with `Bc::count()`, which also returns the
+    /// mutable total, so that swapping a `Bc<T>` for an `AtomicBc<T>` does
+    /// not silently change what `count()` means.
+    pub fn count(&self) -> usize {
+        self.counter
+    }
+
+    /// Get the number of time this structure has been mutably borrowed.
+    pub fn count_mut(&self) -> usize {
+        self.counter
+    }
+
+    /// Get the number of time this structure has been immutably borrowed.
+    ///
+    /// This loads the atomic counter with `Ordering::Relaxed`, so the result
+    /// is eventually consistent across threads.
+    pub fn count_imm(&self) -> usize {
+        self.imm.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for AtomicBc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.imm.fetch_add(1, Ordering::Relaxed);
+        &self.val
+    }
+}
+
+impl<T> DerefMut for AtomicBc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.counter = self.counter.wrapping_add(1);
+        &mut self.val
+    }
+}
+
+/// A small memoization cache built on top of [`Bc<T>`](struct.Bc.html).
+///
+/// `Cache<T, R>` owns the input value `T` (wrapped in a `Bc<T>`) together
+/// with the last computed result `R`. Calling `get_or_compute` only runs the
+/// provided closure again when the value has been mutably borrowed since the
+/// last call, turning the "redo the computation if the value changed"
+/// pattern into a reusable API instead of boilerplate around `Bc::count()`.
+/// Invalidation compares `Generation`s rather than raw counts, so a cached
+/// result can't be mistaken for still valid after `usize::MAX` mutations
+/// wrap `count()` back around.
+pub struct Cache<T, R> {
+    bc: Bc<T>,
+    result: Option<R>,
+    last_gen: Generation,
+}
+
+impl<T, R> Cache<T, R> {
+    /// Create a new `Cache<T, R>` containing the value `val`, with no
+    /// cached result yet.
+    pub fn new(val: T) -> Cache<T, R> {
+        let bc = Bc::new(val);
+        let last_gen = bc.generation();
+        Cache {
+            bc: bc,
+            result: None,
+            last_gen: last_gen,
+        }
+    }
+
+    /// Get the cached result, recomputing it with `f` if the value has been
+    /// mutably borrowed since the last call to `get_or_compute`.
+    pub fn get_or_compute<F>(&mut self, f: F) -> &R where F: FnOnce(&T) -> R {
+        if self.result.is_none() || self.bc.has_changed_since(self.last_gen) {
+            self.result = Some(f(&*self.bc));
+            self.last_gen = self.bc.generation();
+        }
+        self.result.as_ref().unwrap()
+    }
+
+    /// Get a mutable reference to the cached value.
+    ///
+    /// This bumps the borrow count, so the next call to `get_or_compute`
+    /// will recompute the result.
+    pub fn value_mut(&mut self) -> &mut T {
+        &mut self.bc
+    }
+
+    /// Force the next call to `get_or_compute` to recompute the result,
+    /// even if the value has not been mutably borrowed since.
+    pub fn invalidate(&mut self) {
+        self.result = None;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,4 +417,114 @@ mod tests {
         observe(&a);
         assert_eq!(a.count(), 0);
     }
+
+    #[test]
+    fn generation_changes_on_mutation() {
+        let mut a = Bc::new(3);
+        let gen = a.generation();
+        assert!(!a.has_changed_since(gen));
+
+        *a = 18;
+        assert!(a.has_changed_since(gen));
+        assert!(!a.has_changed_since(a.generation()));
+    }
+
+    #[test]
+    fn generation_survives_count_overflow() {
+        let mut a = Bc::new(3);
+        a.counter = usize::MAX - 1;
+
+        let gen = a.generation();
+        *a = 18;
+        *a = 18;
+        assert_eq!(a.count(), 0);
+        assert!(a.has_changed_since(gen));
+    }
+
+    #[test]
+    fn borrow_state() {
+        let mut a = Bc::new(3);
+        assert_eq!(a.state(), BorrowState::Untouched);
+
+        *a = 18;
+        *a = 42;
+        assert_eq!(a.state(), BorrowState::Touched { mutations: 2 });
+
+        a.reset();
+        assert_eq!(a.state(), BorrowState::Untouched);
+    }
+
+    #[test]
+    fn atomic_count() {
+        let mut a = AtomicBc::new(3.0);
+        assert_eq!(a.count(), 0);
+
+        fn observe(_: &f64) {/* Do nothing */}
+        observe(&a);
+        observe(&a);
+        assert_eq!(a.count_imm(), 2);
+        assert_eq!(a.count(), 0);
+
+        *a = 18.0;
+        assert_eq!(a.count(), 1);
+        assert_eq!(a.count_mut(), 1);
+    }
+
+    #[test]
+    fn atomic_reset() {
+        let mut a = AtomicBc::new(3);
+
+        fn observe(_: &i32) {/* Do nothing */}
+        observe(&a);
+        observe(&a);
+        *a = 18;
+        assert_eq!(a.count_imm(), 2);
+        assert_eq!(a.count(), 1);
+
+        a.reset();
+        assert_eq!(a.count(), 0);
+        assert_eq!(a.count_imm(), 0);
+        assert_eq!(a.count_mut(), 0);
+    }
+
+    #[test]
+    fn atomic_sync() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<AtomicBc<i32>>();
+    }
+
+    #[test]
+    fn cache_recomputes_on_mutation() {
+        let mut cache: Cache<Vec<i32>, i32> = Cache::new(vec![1, 2, 3]);
+
+        let mut calls = 0;
+        {
+            let sum = cache.get_or_compute(|v| { calls += 1; v.iter().sum() });
+            assert_eq!(*sum, 6);
+        }
+        {
+            let sum = cache.get_or_compute(|v| { calls += 1; v.iter().sum() });
+            assert_eq!(*sum, 6);
+        }
+        assert_eq!(calls, 1);
+
+        cache.value_mut().push(4);
+        {
+            let sum = cache.get_or_compute(|v| { calls += 1; v.iter().sum() });
+            assert_eq!(*sum, 10);
+        }
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn cache_invalidate() {
+        let mut cache: Cache<i32, i32> = Cache::new(3);
+
+        let mut calls = 0;
+        cache.get_or_compute(|v| { calls += 1; *v * 2 });
+        cache.invalidate();
+        cache.get_or_compute(|v| { calls += 1; *v * 2 });
+
+        assert_eq!(calls, 2);
+    }
 }